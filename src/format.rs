@@ -0,0 +1,260 @@
+//! Recognizes and speaks the two file formats this tool accepts: raw MSU-1
+//! PCM, and Microsoft WAVE (used so tracks can be prepared and inspected in
+//! ordinary audio editors before being fed back in).
+
+use std::{
+    io::{Read, Write, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Which container a file is, or should be written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Msu1,
+    Wav,
+}
+
+impl Format {
+    /// Guesses a file's format from its extension, falling back to sniffing
+    /// the first four bytes (an MSU-1 file starts with `MSU1`, a WAVE file
+    /// with `RIFF`). Leaves `file`'s position at the start either way.
+    pub fn detect(path: &Path, file: &mut (impl Read + Seek)) -> Format {
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            if ext.eq_ignore_ascii_case("wav") {
+                return Format::Wav;
+            } else if ext.eq_ignore_ascii_case("pcm") {
+                return Format::Msu1;
+            }
+        }
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic).expect("Unable to read input file header");
+        file.seek(SeekFrom::Start(0)).expect("Unable to seek input file");
+        if &magic == b"RIFF" { Format::Wav } else { Format::Msu1 }
+    }
+
+    /// Guesses the format an output path should be written in, from its
+    /// extension alone. Anything that isn't recognized as WAVE is written as
+    /// raw MSU-1 PCM, matching this tool's original behavior.
+    pub fn detect_output(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("wav") => Format::Wav,
+            _ => Format::Msu1,
+        }
+    }
+}
+
+/// The part of a track that matters to the reversal code: where the audio
+/// data lives, how long it is, and where (if anywhere) it loops. Produced by
+/// `read_header` for either format, so the reversal code never needs to
+/// know which one it's reading.
+pub struct Input {
+    pub data_start: u64,
+    pub data_len: u64,
+    /// Loop start, in frames from the start of the audio data.
+    pub loop_point: Option<u32>,
+    /// The file's own notion of its sample rate, if it has one. WAVE files
+    /// always do; raw MSU-1 PCM doesn't record one (it's implicitly
+    /// 44100 Hz, but a caller that wants to resample from a different rate
+    /// has to be told what that rate was some other way).
+    pub sample_rate: Option<u32>,
+    /// Set when the importer had to convert the audio on the way in (e.g. a
+    /// mono or non-16-bit WAVE file). When present, the caller should read
+    /// audio from here instead of seeking into the original file at
+    /// `data_start`; `data_start` is 0 and `data_len` already matches it.
+    pub converted_data: Option<Vec<u8>>,
+}
+
+pub fn read_header<T: Read + Seek>(format: Format, file: &mut T) -> Input {
+    match format {
+        Format::Msu1 => read_msu1_header(file),
+        Format::Wav => read_wav_header(file),
+    }
+}
+
+fn read_msu1_header<T: Read + Seek>(file: &mut T) -> Input {
+    let mut buf = [0u8; 8];
+    file.read_exact(&mut buf).expect("Unable to read input file header");
+    if &buf[0..4] != b"MSU1" {
+        panic!("Input file is not an MSU-1 PCM file");
+    }
+    let loop_point = match u32::from_le_bytes(buf[4..8].try_into().unwrap()) {
+        0 => None,
+        x => Some(x),
+    };
+    let file_len = file.seek(SeekFrom::End(0)).expect("Unable to read input file");
+    if !(file_len - 8).is_multiple_of(4) {
+        panic!("Input file has been corrupted, or has had extra data added!");
+    }
+    Input { data_start: 8, data_len: file_len - 8, loop_point, sample_rate: None, converted_data: None }
+}
+
+fn read_wav_header<T: Read + Seek>(file: &mut T) -> Input {
+    let mut riff = [0u8; 12];
+    file.read_exact(&mut riff).expect("Unable to read WAV header");
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        panic!("Input file is not a WAVE file");
+    }
+    let mut format_tag = None;
+    let mut channels = None;
+    let mut bits_per_sample = None;
+    let mut sample_rate = None;
+    let mut data = None;
+    let mut loop_point = None;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        if file.read_exact(&mut chunk_header).is_err() {
+            break;
+        }
+        let chunk_id: [u8; 4] = chunk_header[0..4].try_into().unwrap();
+        let chunk_size = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as u64;
+        let chunk_start = file.stream_position().expect("Unable to read WAV file");
+        match &chunk_id {
+            b"fmt " => {
+                let mut fmt = [0u8; 16];
+                file.read_exact(&mut fmt).expect("Unable to read WAV fmt chunk");
+                format_tag = Some(u16::from_le_bytes(fmt[0..2].try_into().unwrap()));
+                channels = Some(u16::from_le_bytes(fmt[2..4].try_into().unwrap()));
+                sample_rate = Some(u32::from_le_bytes(fmt[4..8].try_into().unwrap()));
+                bits_per_sample = Some(u16::from_le_bytes(fmt[14..16].try_into().unwrap()));
+            },
+            b"data" => {
+                data = Some((chunk_start, chunk_size));
+            },
+            b"smpl" => {
+                let mut smpl = vec![0u8; chunk_size as usize];
+                file.read_exact(&mut smpl).expect("Unable to read WAV smpl chunk");
+                // Offset 28 is numSampleLoops; offset 44 is the first loop's
+                // dwStart, both counted from the start of the chunk body.
+                if smpl.len() >= 48 {
+                    let num_loops = u32::from_le_bytes(smpl[28..32].try_into().unwrap());
+                    if num_loops > 0 {
+                        loop_point = Some(u32::from_le_bytes(smpl[44..48].try_into().unwrap()));
+                    }
+                }
+            },
+            _ => {},
+        }
+        let next_chunk = chunk_start + chunk_size + (chunk_size & 1);
+        file.seek(SeekFrom::Start(next_chunk)).expect("Unable to seek WAV file");
+    }
+    let format_tag = format_tag.expect("WAVE file has no fmt chunk");
+    let channels = channels.expect("WAVE file has no fmt chunk");
+    let bits_per_sample = bits_per_sample.expect("WAVE file has no fmt chunk");
+    let (data_start, data_len) = data.expect("WAVE file has no data chunk");
+    // Already in the one shape the rest of the program speaks: read it
+    // straight off disk, same as the MSU-1 path does.
+    if format_tag == 1 && channels == 2 && bits_per_sample == 16 {
+        return Input { data_start, data_len, loop_point, sample_rate, converted_data: None };
+    }
+    file.seek(SeekFrom::Start(data_start)).expect("Unable to seek WAV file");
+    let mut raw = vec![0u8; data_len as usize];
+    file.read_exact(&mut raw).expect("Unable to read WAV data chunk");
+    let converted = convert_to_16bit_stereo(&raw, channels, bits_per_sample, format_tag);
+    let converted_len = converted.len() as u64;
+    Input { data_start: 0, data_len: converted_len, loop_point, sample_rate, converted_data: Some(converted) }
+}
+
+/// Converts interleaved audio in whatever shape the WAVE `fmt` chunk
+/// described (mono or stereo, 8/16/24/32-bit integer PCM, or 32-bit IEEE
+/// float) to interleaved 16-bit stereo, so the rest of the program never has
+/// to deal with more than one sample format. Mono input is duplicated to
+/// both output channels.
+fn convert_to_16bit_stereo(raw: &[u8], channels: u16, bits_per_sample: u16, format_tag: u16) -> Vec<u8> {
+    if channels != 1 && channels != 2 {
+        panic!("Only mono or stereo WAVE files are supported");
+    }
+    let bytes_per_sample = (bits_per_sample / 8) as usize;
+    let frame_len = bytes_per_sample * channels as usize;
+    let mut out = Vec::with_capacity(raw.len() / frame_len.max(1) * 4);
+    for frame in raw.chunks_exact(frame_len) {
+        let left = sample_to_i16(&frame[0..bytes_per_sample], bits_per_sample, format_tag);
+        let right = if channels == 2 {
+            sample_to_i16(&frame[bytes_per_sample..bytes_per_sample * 2], bits_per_sample, format_tag)
+        } else {
+            left
+        };
+        out.extend_from_slice(&left.to_le_bytes());
+        out.extend_from_slice(&right.to_le_bytes());
+    }
+    out
+}
+
+/// Rescales one sample, in the integer or float shape described by
+/// `bits_per_sample`/`format_tag`, down (or up) to a signed 16-bit sample.
+/// Integer samples keep their most significant 16 bits; float samples are
+/// clamped to [-1, 1] and multiplied by 32767.
+fn sample_to_i16(bytes: &[u8], bits_per_sample: u16, format_tag: u16) -> i16 {
+    match (format_tag, bits_per_sample) {
+        (1, 8) => (((bytes[0] as i32) - 128) << 8) as i16, // 8-bit PCM is unsigned
+        (1, 16) => i16::from_le_bytes(bytes.try_into().unwrap()),
+        (1, 24) => {
+            let aligned = (bytes[0] as i32) << 8 | (bytes[1] as i32) << 16 | (bytes[2] as i32) << 24;
+            (aligned >> 16) as i16
+        },
+        (1, 32) => (i32::from_le_bytes(bytes.try_into().unwrap()) >> 16) as i16,
+        (3, 32) => (f32::from_le_bytes(bytes.try_into().unwrap()).clamp(-1.0, 1.0) * 32767.0) as i16,
+        _ => panic!("Unsupported WAVE sample format (tag {format_tag}, {bits_per_sample}-bit)"),
+    }
+}
+
+/// Writes the part of the header that has to come before the audio data:
+/// for MSU-1, the whole header; for WAVE, `RIFF`/`WAVE`/`fmt `/`data`, sized
+/// to fit `data_len` bytes of audio plus (if `loop_point` is present) the
+/// `smpl` chunk `write_loop_footer` will append afterward.
+pub fn write_header<W: Write>(format: Format, outfile: &mut W, data_len: u64, loop_point: Option<u32>) {
+    match format {
+        Format::Msu1 => {
+            outfile.write_all(b"MSU1").expect("Unable to write output header");
+            outfile.write_all(&loop_point.unwrap_or(0).to_le_bytes()).expect("Unable to write output header");
+        },
+        Format::Wav => {
+            const FMT_CHUNK_LEN: u32 = 16;
+            const SMPL_CHUNK_LEN: u32 = 60;
+            let smpl_chunk_total = if loop_point.is_some() { 8 + SMPL_CHUNK_LEN as u64 } else { 0 };
+            let riff_len = 4
+                + (8 + FMT_CHUNK_LEN as u64)
+                + (8 + data_len)
+                + smpl_chunk_total;
+            outfile.write_all(b"RIFF").expect("Unable to write output header");
+            outfile.write_all(&(riff_len as u32).to_le_bytes()).expect("Unable to write output header");
+            outfile.write_all(b"WAVE").expect("Unable to write output header");
+            outfile.write_all(b"fmt ").expect("Unable to write output header");
+            outfile.write_all(&FMT_CHUNK_LEN.to_le_bytes()).expect("Unable to write output header");
+            outfile.write_all(&1u16.to_le_bytes()).expect("Unable to write output header"); // WAVE_FORMAT_PCM
+            outfile.write_all(&2u16.to_le_bytes()).expect("Unable to write output header"); // channels
+            outfile.write_all(&44100u32.to_le_bytes()).expect("Unable to write output header"); // sample rate
+            outfile.write_all(&(44100u32 * 4).to_le_bytes()).expect("Unable to write output header"); // byte rate
+            outfile.write_all(&4u16.to_le_bytes()).expect("Unable to write output header"); // block align
+            outfile.write_all(&16u16.to_le_bytes()).expect("Unable to write output header"); // bits per sample
+            outfile.write_all(b"data").expect("Unable to write output header");
+            outfile.write_all(&(data_len as u32).to_le_bytes()).expect("Unable to write output header");
+        },
+    }
+}
+
+/// Writes the `smpl` chunk carrying the loop point, for formats (just WAVE,
+/// so far) whose loop metadata comes after the audio data rather than in a
+/// fixed-size header. A no-op for MSU-1, whose loop point is already in the
+/// header `write_header` wrote.
+pub fn write_loop_footer<W: Write>(format: Format, outfile: &mut W, loop_start: u32, loop_end: u32) {
+    if format != Format::Wav {
+        return;
+    }
+    outfile.write_all(b"smpl").expect("Unable to write output footer");
+    outfile.write_all(&60u32.to_le_bytes()).expect("Unable to write output footer");
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // manufacturer
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // product
+    outfile.write_all(&(1_000_000_000 / 44100u32).to_le_bytes()).expect("Unable to write output footer"); // sample period, ns
+    outfile.write_all(&60u32.to_le_bytes()).expect("Unable to write output footer"); // MIDI unity note
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // MIDI pitch fraction
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // SMPTE format
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // SMPTE offset
+    outfile.write_all(&1u32.to_le_bytes()).expect("Unable to write output footer"); // numSampleLoops
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // samplerData
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // CuePointID
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // loop type: forward
+    outfile.write_all(&loop_start.to_le_bytes()).expect("Unable to write output footer");
+    outfile.write_all(&loop_end.to_le_bytes()).expect("Unable to write output footer");
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // fraction
+    outfile.write_all(&0u32.to_le_bytes()).expect("Unable to write output footer"); // play count: infinite
+}
@@ -1,81 +1,390 @@
 use std::{
     fs::File,
-    io::{Read, Write, BufWriter},
+    io::{Read, Write, Seek, SeekFrom, BufWriter, Cursor},
     path::PathBuf,
 };
 
 use rand::prelude::*;
 
+mod format;
+mod resample;
+use format::Format;
+
+// The sample rate MSU-1 playback is fixed to.
+const MSU1_SAMPLE_RATE: u32 = 44100;
+
+/// Either the input file itself, or (once `--resample` has converted it) an
+/// in-memory buffer of the resampled audio. The rest of the program reads
+/// through this without needing to know which.
+enum Source {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Source::File(f) => f.read(buf),
+            Source::Memory(c) => c.read(buf),
+        }
+    }
+}
+
+impl Seek for Source {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Source::File(f) => f.seek(pos),
+            Source::Memory(c) => c.seek(pos),
+        }
+    }
+}
+
 // Natural logarithm of the quietest volume we consider audible.
 const SILENT_LOG: f32 = -6.0;
 
+// Length of the equal-power crossfade applied across grain boundaries in
+// granular reverse mode, chosen to be short enough to read as a seam rather
+// than a separate fade.
+const GRAIN_CROSSFADE_MS: f32 = 5.0;
+
+// Size of the blocks we read from the input file while streaming the
+// reversal, chosen so a block is always a whole number of 4-byte frames.
+const BLOCK_FRAMES: usize = 16384; // 64 KiB
+
 use clap::Parser;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = Some("Given an MSU-1 formatted .pcm file, creates a reversed version of that file. Information is lost; if there's an original introduction, it isn't transferred to the new file, and the loop is offsetted by a few seconds!") )]
+#[command(author, version, about, long_about = Some("Given an MSU-1 formatted .pcm file (or a .wav file with equivalent loop metadata in a smpl chunk), creates a reversed version of that file. Information is lost; if there's an original introduction, it isn't transferred to the new file, and by default the loop is offsetted by a few seconds (use --seamless-loop for a true click-free loop instead)!") )]
 struct Invocation {
-    /// Input PCM file
+    /// Input file: MSU-1 .pcm, or .wav
     #[arg()]
     infile: PathBuf,
-    /// Output PCM file
+    /// Output file: MSU-1 .pcm, or .wav
     #[arg()]
     outfile: PathBuf,
     /// Number of seconds of fade in (doesn't apply to tracks with a zero loop
     /// point)
     #[arg(short, long, default_value = "3.0")]
     fade_time: f32,
+    /// Instead of reversing the whole track end-to-end, split it into
+    /// grains of this many seconds and reverse the frame order within each
+    /// grain while keeping the grains themselves in forward order. Produces
+    /// a "stutter-backwards" texture rather than a clean reversal; disables
+    /// the fade-in/loop-offset behavior above
+    #[arg(long)]
+    grain_size: Option<f32>,
+    /// Resample the input to 44100 Hz (MSU-1's fixed playback rate) before
+    /// reversing it, instead of assuming it's already there
+    #[arg(long)]
+    resample: bool,
+    /// Sample rate of the input, for raw PCM input that doesn't carry one
+    /// in its header. Required to use --resample on a .pcm file; ignored
+    /// for .wav input, which already states its own rate
+    #[arg(long)]
+    input_rate: Option<u32>,
+    /// Apply first-order error-feedback noise shaping to the fade-in's
+    /// dither, pushing its quantization noise toward higher frequencies
+    /// instead of leaving it flat
+    #[arg(long)]
+    noise_shaping: bool,
+    /// Instead of a one-shot fade-in, crossfade the loop seam itself: blend
+    /// the reversed tail (approaching the loop point) into the reversed head
+    /// (the new loop start) over `--fade-time` seconds, and loop from frame
+    /// 0. The file then loops click-free at the intended point instead of
+    /// being offset by a few seconds
+    #[arg(long)]
+    seamless_loop: bool,
 }
 
-fn read_header<T: Read>(file: &mut T) -> Option<u32> {
-    let mut buf = [0u8; 8];
-    file.read_exact(&mut buf).expect("Unable to read input file header");
-    if &buf[0..4] != b"MSU1" {
-        panic!("Input file is not an MSU-1 PCM file");
+/// Yields the audio data found between `data_start` and `data_start +
+/// data_len` in `reader`, one block at a time, starting from the end of
+/// the region and working backward. Each block is still in forward frame
+/// order; callers reverse the frames within a block themselves. Keeping
+/// blocks to `BLOCK_FRAMES` frames bounds peak memory use to O(block)
+/// regardless of how long the input is.
+struct BackwardBlocks<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    data_start: u64,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek> BackwardBlocks<'a, R> {
+    fn new(reader: &'a mut R, data_start: u64, data_len: u64) -> Self {
+        BackwardBlocks { reader, data_start, pos: data_len }
     }
-    match u32::from_le_bytes(buf[4..8].try_into().unwrap()) {
-        0 => None,
-        x => Some(x)
+}
+
+impl<'a, R: Read + Seek> Iterator for BackwardBlocks<'a, R> {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.pos == 0 {
+            return None;
+        }
+        let block_len = (BLOCK_FRAMES * 4).min(self.pos as usize);
+        let start = self.pos - block_len as u64;
+        self.reader.seek(SeekFrom::Start(self.data_start + start))
+            .expect("Unable to seek input file");
+        let mut buf = vec![0u8; block_len];
+        self.reader.read_exact(&mut buf).expect("Unable to read audio data");
+        self.pos = start;
+        Some(buf)
     }
 }
 
-fn write_reversed<T: Write>(outfile: &mut T, buf: &[u8]) {
-    for chunk in buf.chunks(4).rev() {
-        outfile.write_all(chunk).expect("Unable to write audio data");
+/// Like `BackwardBlocks`, but cycles: once it reaches the start of the
+/// region it wraps back around to the end, and keeps going until
+/// `remaining` frames have been yielded in total. Used to stream the
+/// fade-in's wraparound without buffering the whole track.
+struct CyclicBackwardBlocks<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    data_start: u64,
+    total_frames: u64,
+    // One past the next frame index to read, going downward.
+    pos: u64,
+    remaining: u64,
+}
+
+impl<'a, R: Read + Seek> CyclicBackwardBlocks<'a, R> {
+    fn new(reader: &'a mut R, data_start: u64, total_frames: u64, start_pos: u64, remaining: u64) -> Self {
+        CyclicBackwardBlocks { reader, data_start, total_frames, pos: start_pos + 1, remaining }
     }
 }
 
-fn write_reversed_with_fadein<T: Write>(outfile: &mut T, buf: &[u8], fade_samples: usize) {
+impl<'a, R: Read + Seek> Iterator for CyclicBackwardBlocks<'a, R> {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let block_frames = (BLOCK_FRAMES as u64).min(self.remaining).min(self.pos) as usize;
+        let start_frame = self.pos - block_frames as u64;
+        self.reader.seek(SeekFrom::Start(self.data_start + start_frame * 4))
+            .expect("Unable to seek input file");
+        let mut buf = vec![0u8; block_frames * 4];
+        self.reader.read_exact(&mut buf).expect("Unable to read audio data");
+        self.remaining -= block_frames as u64;
+        self.pos = if start_frame == 0 { self.total_frames } else { start_frame };
+        Some(buf)
+    }
+}
+
+fn write_reversed<R: Read + Seek, W: Write>(infile: &mut R, outfile: &mut W, data_start: u64, data_len: u64) {
+    for block in BackwardBlocks::new(infile, data_start, data_len) {
+        for chunk in block.chunks(4).rev() {
+            outfile.write_all(chunk).expect("Unable to write audio data");
+        }
+    }
+}
+
+fn read_frame<R: Read + Seek>(infile: &mut R, data_start: u64, frame_index: u64) -> [u8; 4] {
+    infile.seek(SeekFrom::Start(data_start + frame_index * 4))
+        .expect("Unable to seek input file");
+    let mut buf = [0u8; 4];
+    infile.read_exact(&mut buf).expect("Unable to read audio data");
+    buf
+}
+
+// Index, relative to the start of the loopable region, of the frame that
+// the `i`th output frame of the fade-in's `.cycle()`-like sequence reads
+// from. This is the same sequence whether `i` falls in the fade region or
+// the bulk copy that follows it, so both loops below can share it.
+fn cycled_frame_index(i: u64, total_frames: u64) -> u64 {
+    (total_frames as i64 - 1 - (i as i64 % total_frames as i64))
+        .rem_euclid(total_frames as i64) as u64
+}
+
+/// Truncates `full_precision + prev_err` (scaled the same way as the s16
+/// input, left shifted 16 bits) down to a dithered `i16`, and returns the
+/// quantization error to feed into the next sample. `prev_err` should be 0
+/// when noise shaping is disabled.
+fn quantize_with_dither(full_precision: i32, prev_err: i32, dither: i32) -> (i16, i32) {
+    let shaped = full_precision + prev_err;
+    let quantized = ((shaped + dither) >> 16) as i16;
+    let err = shaped - ((quantized as i32) << 16);
+    (quantized, err)
+}
+
+fn write_reversed_with_fadein<R: Read + Seek, W: Write>(infile: &mut R, outfile: &mut W, data_start: u64, total_frames: u64, fade_samples: usize, noise_shaping: bool) {
     let mut rng = thread_rng();
-    // one half bit of dither
-    let distribution = rand::distributions::Uniform::new_inclusive(-32768, 32768);
-    let mut iter = buf.chunks(4).rev().cycle();
-    let mut fade_rem = fade_samples;
-    for chunk in &mut iter {
+    // Two summed quarter-LSB uniforms give a triangular-PDF dither spanning
+    // one LSB peak-to-peak. Unlike a single uniform sample (RPDF), its noise
+    // floor isn't modulated by the signal, which matters on a long fade to
+    // silence.
+    let dither_quarter = rand::distributions::Uniform::new_inclusive(-16384, 16384);
+    let mut left_err = 0i32;
+    let mut right_err = 0i32;
+    for i in 0..fade_samples as u64 {
+        let idx = cycled_frame_index(i, total_frames);
+        let chunk = read_frame(infile, data_start, idx);
         let left = i16::from_le_bytes(chunk[0..2].try_into().unwrap());
-        let right = i16::from_le_bytes(chunk[0..2].try_into().unwrap());
+        let right = i16::from_le_bytes(chunk[2..4].try_into().unwrap());
+        let fade_rem = fade_samples as u64 - i;
         let fade_magnitude = ((SILENT_LOG * (fade_rem as f32) / (fade_samples as f32)).exp() * 65536.0) as i32;
-        let left = ((left as i32 * fade_magnitude + rng.sample(distribution)) >> 16) as i16;
-        let right = ((right as i32 * fade_magnitude + rng.sample(distribution)) >> 16) as i16;
+        let (left, new_left_err) = quantize_with_dither(
+            left as i32 * fade_magnitude,
+            if noise_shaping { left_err } else { 0 },
+            rng.sample(dither_quarter) + rng.sample(dither_quarter),
+        );
+        let (right, new_right_err) = quantize_with_dither(
+            right as i32 * fade_magnitude,
+            if noise_shaping { right_err } else { 0 },
+            rng.sample(dither_quarter) + rng.sample(dither_quarter),
+        );
+        left_err = new_left_err;
+        right_err = new_right_err;
         let mut faded_chunk = [0u8; 4];
         faded_chunk[0..2].clone_from_slice(&left.to_le_bytes());
         faded_chunk[2..4].clone_from_slice(&right.to_le_bytes());
         outfile.write_all(&faded_chunk).expect("Unable to write audio data");
-        fade_rem -= 1;
-        if fade_rem == 0 {
-            break;
+    }
+    // Resume the same cycling sequence for one full pass of the loopable
+    // region, this time writing frames unmodified; stream it in blocks
+    // since there's no per-frame fade math left to do.
+    let start_pos = cycled_frame_index(fade_samples as u64, total_frames);
+    for block in CyclicBackwardBlocks::new(infile, data_start, total_frames, start_pos, total_frames) {
+        for chunk in block.chunks(4).rev() {
+            outfile.write_all(chunk).expect("Unable to write audio data");
         }
     }
-    let mut rem_chunks = buf.len() / 4;
-    for chunk in iter {
-        outfile.write_all(chunk).expect("Unable to write audio data");
-        rem_chunks -= 1;
-        if rem_chunks == 0 {
-            break;
+}
+
+/// Builds a true seamless loop out of the loopable region: reverses it, and
+/// crossfades its own seam so the file can loop from frame 0 without a
+/// click. `crossfade_frames` (clamped to at most half the region) frames of
+/// the reversed tail -- the frames approaching the loop point, played last
+/// in reversed order -- are blended with the same number of frames from the
+/// reversed head -- the file's last frames, played first once reversed --
+/// and written once at the very start of the output; the untouched middle of
+/// the reversed region follows via the ordinary streaming reverser. Like the
+/// granular crossfade, this is an overlap-add: the output is
+/// `crossfade_frames` frames shorter than `total_frames`.
+fn write_seamless_loop<R: Read + Seek, W: Write>(infile: &mut R, outfile: &mut W, data_start: u64, total_frames: u64, crossfade_frames: u64) {
+    let crossfade_frames = crossfade_frames.min(total_frames / 2);
+    if crossfade_frames > 0 {
+        let crossfade_len = (crossfade_frames * 4) as usize;
+        // The reversed tail is just the region's first `crossfade_frames`
+        // frames, read in forward order and indexed back-to-front below.
+        let mut tail = vec![0u8; crossfade_len];
+        infile.seek(SeekFrom::Start(data_start)).expect("Unable to seek input file");
+        infile.read_exact(&mut tail).expect("Unable to read audio data");
+        // The reversed head is the region's last `crossfade_frames` frames,
+        // same deal.
+        let mut head = vec![0u8; crossfade_len];
+        infile.seek(SeekFrom::Start(data_start + (total_frames - crossfade_frames) * 4)).expect("Unable to seek input file");
+        infile.read_exact(&mut head).expect("Unable to read audio data");
+        for i in 0..crossfade_frames as usize {
+            let t = (i as f32 + 0.5) / crossfade_frames as f32;
+            let fade_out = (std::f32::consts::FRAC_PI_2 * t).cos();
+            let fade_in = (std::f32::consts::FRAC_PI_2 * t).sin();
+            let tail_frame = &tail[(crossfade_frames as usize - 1 - i) * 4..][..4];
+            let head_frame = &head[(crossfade_frames as usize - 1 - i) * 4..][..4];
+            for ch in 0..2 {
+                let prev = i16::from_le_bytes(tail_frame[ch*2..ch*2+2].try_into().unwrap()) as f32;
+                let next = i16::from_le_bytes(head_frame[ch*2..ch*2+2].try_into().unwrap()) as f32;
+                let mixed = (prev * fade_out + next * fade_in).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                outfile.write_all(&mixed.to_le_bytes()).expect("Unable to write audio data");
+            }
         }
     }
+    let middle_start = data_start + crossfade_frames * 4;
+    let middle_len = (total_frames - crossfade_frames * 2) * 4;
+    write_reversed(infile, outfile, middle_start, middle_len);
 }
 
+/// Mirrors the grain-length and crossfade-length bookkeeping done by
+/// `write_granular_reversed`, without touching any sample data, so the
+/// caller can learn the exact output frame count before writing a header
+/// that has to declare it (crossfading two grains together is an
+/// overlap-add: it shortens the total by the crossfade length at each
+/// boundary, the same way splicing two clips with a crossfade does).
+///
+/// When `loop_point` is given, `lead_grain_frames` is assumed to have been
+/// chosen (as `loop_point % grain_frames`) so that it falls exactly on a
+/// grain boundary; the output frame at which that boundary lands is
+/// returned alongside the total, for the caller to write into the loop
+/// footer instead of the input's unshrunk `loop_point`.
+fn granular_reversed_frame_count(total_frames: u64, grain_frames: u64, lead_grain_frames: u64, crossfade_frames: u64, loop_point: Option<u64>) -> (u64, Option<u64>) {
+    let mut remaining = total_frames;
+    let mut grain_len = if lead_grain_frames > 0 { lead_grain_frames } else { grain_frames };
+    let mut held_frames = 0u64;
+    let mut output_frames = 0u64;
+    let mut consumed_frames = 0u64;
+    let mut loop_output_frame = if loop_point == Some(0) { Some(0) } else { None };
+    while remaining > 0 {
+        let this_len = grain_len.min(remaining);
+        remaining -= this_len;
+        let fade_len = crossfade_frames.min(this_len / 2).min(held_frames);
+        let tail_len = crossfade_frames.min(this_len / 2);
+        let tail_start = this_len.saturating_sub(tail_len).max(fade_len);
+        output_frames += (held_frames - fade_len) + fade_len + (tail_start - fade_len);
+        held_frames = this_len - tail_start;
+        consumed_frames += this_len;
+        grain_len = grain_frames;
+        if loop_output_frame.is_none() && Some(consumed_frames) == loop_point {
+            loop_output_frame = Some(output_frames);
+        }
+    }
+    (output_frames + held_frames, loop_output_frame)
+}
 
+/// Splits the audio data into consecutive grains of `grain_frames` frames
+/// (the first grain is `lead_grain_frames` long instead, if nonzero, so
+/// that later grain boundaries land on a loop point) and reverses the frame
+/// order within each grain while leaving the grains in forward order.
+/// Adjacent grains are joined with a short equal-power crossfade so the
+/// discontinuity at each boundary doesn't click.
+fn write_granular_reversed<R: Read + Seek, W: Write>(
+    infile: &mut R,
+    outfile: &mut W,
+    data_start: u64,
+    data_len: u64,
+    grain_frames: u64,
+    lead_grain_frames: u64,
+    crossfade_frames: u64,
+) {
+    infile.seek(SeekFrom::Start(data_start)).expect("Unable to seek input file");
+    // Raw LE bytes from the end of the previous grain, held back so they can
+    // be blended with the start of the next one instead of written plain.
+    let mut held_tail: Vec<u8> = Vec::new();
+    let mut remaining_frames = data_len / 4;
+    let mut grain_len = if lead_grain_frames > 0 { lead_grain_frames } else { grain_frames };
+    while remaining_frames > 0 {
+        let this_len = grain_len.min(remaining_frames);
+        remaining_frames -= this_len;
+        let mut buf = vec![0u8; (this_len * 4) as usize];
+        infile.read_exact(&mut buf).expect("Unable to read audio data");
+        let mut reversed = Vec::with_capacity(buf.len());
+        for chunk in buf.chunks(4).rev() {
+            reversed.extend_from_slice(chunk);
+        }
+        let held_frames = (held_tail.len() / 4) as u64;
+        let fade_len = crossfade_frames.min(this_len / 2).min(held_frames) as usize;
+        // Anything held back beyond what this grain is short enough to
+        // blend with couldn't have been faded anyway; flush it plain before
+        // the blended region so none of it is lost.
+        let plain_tail_end = (held_frames as usize - fade_len) * 4;
+        outfile.write_all(&held_tail[..plain_tail_end]).expect("Unable to write audio data");
+        let fade_tail = &held_tail[plain_tail_end..];
+        for i in 0..fade_len {
+            let t = (i as f32 + 0.5) / fade_len as f32;
+            let fade_out = (std::f32::consts::FRAC_PI_2 * t).cos();
+            let fade_in = (std::f32::consts::FRAC_PI_2 * t).sin();
+            for ch in 0..2 {
+                let prev = i16::from_le_bytes(fade_tail[i*4+ch*2..i*4+ch*2+2].try_into().unwrap()) as f32;
+                let next = i16::from_le_bytes(reversed[i*4+ch*2..i*4+ch*2+2].try_into().unwrap()) as f32;
+                let mixed = (prev * fade_out + next * fade_in).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                outfile.write_all(&mixed.to_le_bytes()).expect("Unable to write audio data");
+            }
+        }
+        let body_start = fade_len * 4;
+        let tail_len = crossfade_frames.min(this_len / 2) as usize * 4;
+        let tail_start = reversed.len().saturating_sub(tail_len).max(body_start);
+        outfile.write_all(&reversed[body_start..tail_start]).expect("Unable to write audio data");
+        held_tail = reversed[tail_start..].to_vec();
+        grain_len = grain_frames;
+    }
+    outfile.write_all(&held_tail).expect("Unable to write audio data");
+}
 
 fn main() {
     let invocation = Invocation::parse();
@@ -86,26 +395,81 @@ fn main() {
         panic!("Ridiculously long fade time.");
     }
     let fade_samples = (invocation.fade_time * 44100.0 + 0.5).floor() as usize;
-    let mut infile = File::open(&invocation.infile).expect("Unable to open input file");
-    let loop_point = read_header(&mut infile);
-    let mut all = vec![];
-    infile.read_to_end(&mut all).expect("Unable to read input file");
-    if all.len() % 4 != 0 {
-        panic!("Input file has been corrupted, or has had extra data added!");
+    let mut infile = Source::File(File::open(&invocation.infile).expect("Unable to open input file"));
+    let input_format = Format::detect(&invocation.infile, &mut infile);
+    let mut input = format::read_header(input_format, &mut infile);
+    if let Some(converted) = input.converted_data.take() {
+        input.data_start = 0;
+        input.data_len = converted.len() as u64;
+        infile = Source::Memory(Cursor::new(converted));
+    }
+    if invocation.resample {
+        let input_rate = input.sample_rate.or(invocation.input_rate)
+            .expect("--input-rate is required to resample a raw PCM file (its header doesn't carry a sample rate)");
+        if input_rate != MSU1_SAMPLE_RATE {
+            let mut raw = vec![0u8; input.data_len as usize];
+            infile.seek(SeekFrom::Start(input.data_start)).expect("Unable to seek input file");
+            infile.read_exact(&mut raw).expect("Unable to read audio data");
+            let resampled = resample::resample_stereo(&raw, input_rate, MSU1_SAMPLE_RATE);
+            let loop_point = input.loop_point.map(|lp| resample::resample_loop_point(lp, input_rate, MSU1_SAMPLE_RATE));
+            let data_len = resampled.len() as u64;
+            infile = Source::Memory(Cursor::new(resampled));
+            input = format::Input { data_start: 0, data_len, loop_point, sample_rate: Some(MSU1_SAMPLE_RATE), converted_data: None };
+        }
     }
+    // Checked here rather than right after reading the header: resampling
+    // rounds the frame count and the loop point independently (floor vs.
+    // round-to-nearest), so a loop point comfortably inside the original
+    // file can land at or past the end of the resampled one.
+    if let Some(loop_point) = input.loop_point {
+        if loop_point as u64 >= input.data_len / 4 {
+            panic!("Loop point is at or past the end of the input audio; there would be nothing left to reverse or loop.");
+        }
+    }
+    let output_format = Format::detect_output(&invocation.outfile);
     let mut outfile = BufWriter::new(File::create(&invocation.outfile).expect("Unable to open output file"));
-    outfile.write_all(b"MSU1").expect("Unable to write output header");
-    match loop_point {
+    if let Some(grain_size) = invocation.grain_size {
+        if !grain_size.is_finite() || grain_size <= 0.0 {
+            panic!("Invalid grain size. Must be positive.");
+        }
+        let grain_frames = (grain_size * 44100.0 + 0.5).floor() as u64;
+        if grain_frames == 0 {
+            panic!("Grain size is too short to contain any frames.");
+        }
+        let crossfade_frames = (GRAIN_CROSSFADE_MS / 1000.0 * 44100.0).round() as u64;
+        let lead_grain_frames = input.loop_point.map(|lp| lp as u64 % grain_frames).unwrap_or(0);
+        // Crossfading grains together is an overlap-add, so the output is a
+        // little shorter than the input; work out by exactly how much
+        // before writing a header that has to declare the final length.
+        let (total_output_frames, loop_output_frame) = granular_reversed_frame_count(input.data_len / 4, grain_frames, lead_grain_frames, crossfade_frames, input.loop_point.map(|lp| lp as u64));
+        format::write_header(output_format, &mut outfile, total_output_frames * 4, loop_output_frame.map(|f| f as u32));
+        write_granular_reversed(&mut infile, &mut outfile, input.data_start, input.data_len, grain_frames, lead_grain_frames, crossfade_frames);
+        if let Some(loop_output_frame) = loop_output_frame {
+            format::write_loop_footer(output_format, &mut outfile, loop_output_frame as u32, (total_output_frames - 1) as u32);
+        }
+        return;
+    }
+    match input.loop_point {
         None => {
             // It's simple, we reverse the Batman
-            outfile.write_all(&[0u8;4]).expect("Unable to write output header");
-            write_reversed(&mut outfile, &all);
+            format::write_header(output_format, &mut outfile, input.data_len, None);
+            write_reversed(&mut infile, &mut outfile, input.data_start, input.data_len);
         },
         Some(loop_point) => {
-            outfile.write_all(&fade_samples.to_le_bytes()).expect("Unable to write output header");
-            let start_offset = (loop_point as usize) * 4;
-            let all = &all[start_offset..];
-            write_reversed_with_fadein(&mut outfile, all, fade_samples);
+            let data_start = input.data_start + (loop_point as u64) * 4;
+            let total_frames = (input.data_start + input.data_len - data_start) / 4;
+            if invocation.seamless_loop {
+                let crossfade_frames = (fade_samples as u64).min(total_frames / 2);
+                let total_output_frames = total_frames - crossfade_frames;
+                format::write_header(output_format, &mut outfile, total_output_frames * 4, Some(0));
+                write_seamless_loop(&mut infile, &mut outfile, data_start, total_frames, fade_samples as u64);
+                format::write_loop_footer(output_format, &mut outfile, 0, (total_output_frames - 1) as u32);
+            } else {
+                let total_output_frames = fade_samples as u64 + total_frames;
+                format::write_header(output_format, &mut outfile, total_output_frames * 4, Some(fade_samples as u32));
+                write_reversed_with_fadein(&mut infile, &mut outfile, data_start, total_frames, fade_samples, invocation.noise_shaping);
+                format::write_loop_footer(output_format, &mut outfile, fade_samples as u32, (total_output_frames - 1) as u32);
+            }
         },
     }
 }
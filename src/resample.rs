@@ -0,0 +1,90 @@
+//! Windowed-sinc (Kaiser) polyphase resampling, used to bring source
+//! material that isn't already 44100 Hz (MSU-1's fixed playback rate) in
+//! line with it before reversing.
+
+use std::f64::consts::PI;
+
+// Taps on each side of the filter's center; wider taps trade CPU time for a
+// cleaner stopband.
+const TAPS_PER_SIDE: i64 = 16;
+const KAISER_BETA: f64 = 8.0;
+
+// Modified Bessel function of the first kind, order 0, via its power
+// series. Used to build the Kaiser window.
+fn bessel_i0(x: f64) -> f64 {
+    let y = x * x / 4.0;
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for k in 1..=20 {
+        term *= y / (k * k) as f64;
+        sum += term;
+    }
+    sum
+}
+
+fn kaiser_window(x: f64) -> f64 {
+    if !(-1.0..=1.0).contains(&x) {
+        return 0.0;
+    }
+    bessel_i0(KAISER_BETA * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+/// Value of the windowed-sinc low-pass filter, with cutoff `fc` expressed
+/// as a fraction of the input sample rate, at an offset of `t` input
+/// samples from its center.
+fn filter_tap(t: f64, fc: f64) -> f64 {
+    2.0 * fc * sinc(2.0 * fc * t) * kaiser_window(t / (TAPS_PER_SIDE as f64 + 1.0))
+}
+
+fn resample_channel(samples: &[i16], input_rate: u32, output_rate: u32, output_len: usize) -> Vec<i16> {
+    // Cutoff at whichever Nyquist is lower, so both upsampling (avoiding
+    // images) and downsampling (avoiding aliasing) are handled by the same
+    // filter.
+    let fc = 0.5 * input_rate.min(output_rate) as f64 / input_rate as f64;
+    let ratio = input_rate as f64 / output_rate as f64;
+    let mut out = Vec::with_capacity(output_len);
+    for n in 0..output_len {
+        let input_pos = n as f64 * ratio;
+        let center = input_pos.floor() as i64;
+        let mut acc = 0.0;
+        for k in -TAPS_PER_SIDE..=TAPS_PER_SIDE {
+            let idx = center + k;
+            if idx < 0 || idx as usize >= samples.len() {
+                continue;
+            }
+            acc += samples[idx as usize] as f64 * filter_tap(idx as f64 - input_pos, fc);
+        }
+        out.push(acc.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+    }
+    out
+}
+
+/// Resamples interleaved 16-bit stereo frames from `input_rate` to
+/// `output_rate`, processing left and right independently.
+pub fn resample_stereo(frames: &[u8], input_rate: u32, output_rate: u32) -> Vec<u8> {
+    let frame_count = frames.len() / 4;
+    let mut left = Vec::with_capacity(frame_count);
+    let mut right = Vec::with_capacity(frame_count);
+    for chunk in frames.chunks(4) {
+        left.push(i16::from_le_bytes(chunk[0..2].try_into().unwrap()));
+        right.push(i16::from_le_bytes(chunk[2..4].try_into().unwrap()));
+    }
+    let output_len = (frame_count as u64 * output_rate as u64 / input_rate as u64) as usize;
+    let left = resample_channel(&left, input_rate, output_rate, output_len);
+    let right = resample_channel(&right, input_rate, output_rate, output_len);
+    let mut out = Vec::with_capacity(output_len * 4);
+    for (l, r) in left.iter().zip(right.iter()) {
+        out.extend_from_slice(&l.to_le_bytes());
+        out.extend_from_slice(&r.to_le_bytes());
+    }
+    out
+}
+
+/// Remaps a loop point (in frames) from one sample rate to another.
+pub fn resample_loop_point(loop_point: u32, input_rate: u32, output_rate: u32) -> u32 {
+    ((loop_point as u64 * output_rate as u64 + input_rate as u64 / 2) / input_rate as u64) as u32
+}